@@ -50,77 +50,342 @@
 //! });
 //! assert_eq!(result2, 10);
 //! ```
+//!
+//! When the value being broken with doesn't share a single type `T` across
+//! every scope that might break to it, see `DynBreakTarget`, which accepts
+//! any `Any + Send` value in exchange for the caller downcasting the result.
 
+use std::any::Any;
+use std::backtrace::Backtrace;
 use std::panic;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+thread_local! {
+    // Scratch space used by `deploy_quiet` to smuggle the panic message text
+    // out of the panic hook, which doesn't otherwise have a way to hand data
+    // back to its caller.
+    static CAPTURED_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // How many `deploy_quiet` calls are currently on this thread's stack.
+    // Only the outermost one installs the capture hook and holds
+    // `PANIC_HOOK_LOCK`; nested calls on the same thread piggyback on it
+    // instead of re-locking, which would deadlock against itself.
+    static QUIET_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
 
-/// A BreakRequest is a dummy zero-sized-type. It's heap address is used to
-/// identify which BreakTarget we are breaking towards.
-struct BreakRequest;
+/// `panic::take_hook`/`set_hook` operate on a single process-wide slot, so
+/// `deploy_quiet`'s take-run-restore sequence only round-trips correctly if
+/// calls are serialized: two overlapping calls would otherwise each take
+/// what the other just installed, and whichever restores last clobbers the
+/// real hook permanently. This lock makes overlapping calls queue instead.
+/// It's only ever taken by the outermost `deploy_quiet` call on a given
+/// thread (see `QUIET_DEPTH`), so same-thread nesting doesn't deadlock on it.
+static PANIC_HOOK_LOCK: Mutex<()> = Mutex::new(());
+
+/// A process-wide counter used to hand out unique ids to each `deploy` call,
+/// so a `break_with` panic can be routed back to the exact `BreakTarget` that
+/// produced it instead of relying on address comparisons. Wrapping after
+/// 2^64 deploys would in principle allow a collision, but that many deploys
+/// happening while one of the old ids is still live on the stack is not a
+/// practical concern.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A BreakRequest is the panic payload used to unwind toward a BreakTarget.
+/// Its `id` is compared against the target's own id to determine whether a
+/// given panic is the break we're looking for.
+struct BreakRequest {
+    id: u64,
+}
 
 /// This object represents the target stack frame which we will unwind toward
 /// when the break_with method is invoked. The value which we are breaking with
 /// will be stored within the BreakTarget to be returned when control flow
 /// resumes.
 #[derive(Debug)]
-pub struct BreakTarget<T>(RefCell<Option<T>>);
+pub struct BreakTarget<T> {
+    id: u64,
+    value: RefCell<Option<T>>,
+    location: Cell<Option<&'static Location<'static>>>,
+    // Whether break_with should capture a backtrace. Left false (and the
+    // backtrace cell untouched) for every deploy variant except
+    // `deploy_traced`, keeping the common path free of capture overhead.
+    trace: bool,
+    backtrace: RefCell<Option<Backtrace>>,
+}
+
+/// The result of running a deployed closure to completion, before a public
+/// `deploy*` method adapts it to its own return type. Shared by every
+/// `BreakTarget::deploy*` method so the target construction and
+/// marker-identification dispatch live in one place.
+enum Resolution<T> {
+    /// The closure returned normally, producing this value.
+    Returned(T),
+    /// The closure called `break_with` on the target that's running it.
+    Broke(T),
+    /// The closure panicked for a reason unrelated to this target: either a
+    /// genuine panic, or a `break_with` aimed at some other (e.g. outer)
+    /// BreakTarget.
+    Foreign(Box<dyn Any + Send>),
+}
 
 impl<T> BreakTarget<T> {
+    /// Construct a fresh target with a new unique id.
+    fn new(trace: bool) -> Self {
+        BreakTarget {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            value: RefCell::new(None),
+            location: Cell::new(None),
+            trace,
+            backtrace: RefCell::new(None),
+        }
+    }
+
+    /// Run `func` against a freshly constructed target, catching any panic it
+    /// raises and classifying it against that target's id. Returns the
+    /// target alongside the `Resolution` so callers that need the recorded
+    /// location or backtrace can still read it off a `Broke` result.
+    fn run<F>(trace: bool, func: F) -> (Resolution<T>, BreakTarget<T>)
+    where
+        F: FnOnce(&BreakTarget<T>) -> T,
+    {
+        let target = BreakTarget::new(trace);
+
+        let resolution = match panic::catch_unwind(panic::AssertUnwindSafe(|| func(&target))) {
+            Ok(v) => Resolution::Returned(v),
+            Err(panic_val) => match panic_val.downcast_ref::<BreakRequest>() {
+                // Check if the panic we got back is tagged with our id. If it
+                // is, it was triggered by our break_with function.
+                Some(req) if req.id == target.id => {
+                    Resolution::Broke(target.value.borrow_mut().take().unwrap())
+                }
+                _ => Resolution::Foreign(panic_val),
+            },
+        };
+
+        (resolution, target)
+    }
+
     /// Deploy a break target. The target will be passed by reference to the
     /// argument closure. The BreakTarget object provides a single `break_with`
     /// method, which can be invoked to halt execution and return control to the
     /// deployment site. If the `break_with` function was not invoked, the
     /// return value of the closure will instead be produced.
     pub fn deploy<F>(func: F) -> T where F: FnOnce(&BreakTarget<T>) -> T {
-        // A place for storing the information if the function aborts during its
-        // execution. The address of this local is also used as a marker value
-        // for the panic value when break_with is called, allowing us to resume
-        // without parforming somewhat expensive downcasts.
-        let target = BreakTarget(RefCell::new(None));
+        match Self::run(false, func).0 {
+            Resolution::Returned(v) | Resolution::Broke(v) => v,
+            Resolution::Foreign(panic_val) => panic::resume_unwind(panic_val),
+        }
+    }
 
-        // Run the logic, catching any panics triggered
-        match panic::catch_unwind(panic::AssertUnwindSafe(|| func(&target))) {
-            Ok(v) => v,
-            Err(panic_val) => {
-                if let Some(panic_ptr) = panic_val.downcast_ref::<BreakRequest>() {
-                    // Check if the panic we got back has a data pointer which
-                    // refers to our break target. If it does, it was triggered
-                    // by our break_with function.
-                    if panic_ptr as *const _ as *const Self == &target as *const _ {
-                        return target.0.into_inner().unwrap();
-                    }
-                }
+    /// Like `deploy`, but instead of silently re-raising panics which don't
+    /// belong to this target, classifies the result of running the closure
+    /// into an `Outcome`: a normal return, a break, or a foreign panic handed
+    /// back to the caller rather than propagated. This lets callers implement
+    /// their own handling (logging, retrying, etc.) for panics which occur
+    /// alongside break_with calls, without installing their own
+    /// `catch_unwind`.
+    pub fn try_deploy<F>(func: F) -> Outcome<T> where F: FnOnce(&BreakTarget<T>) -> T {
+        match Self::run(false, func).0 {
+            Resolution::Returned(v) => Outcome::Returned(v),
+            Resolution::Broke(v) => Outcome::Broke(v),
+            Resolution::Foreign(panic_val) => Outcome::Panicked(panic_val),
+        }
+    }
 
-                panic::resume_unwind(panic_val);
+    /// Like `try_deploy`, but additionally suppresses the default panic
+    /// hook's stderr output for the duration of the closure, capturing any
+    /// genuine panic's message text instead of letting it reach the
+    /// terminal. `break_with` unwinds via `resume_unwind` rather than
+    /// `panic!`, so it never invokes the hook and breaks are already silent;
+    /// this only matters for foreign panics racing with break_with calls.
+    /// The captured text, if any, is returned alongside the `Outcome` (it is
+    /// always `None` unless the outcome is `Panicked`).
+    ///
+    /// The panic hook is a process-wide resource, so overlapping calls to
+    /// this method from *different* threads contend for it: they are
+    /// serialized on a global lock rather than running independently, and a
+    /// call blocks until every other in-flight `deploy_quiet` call on
+    /// another thread has restored the hook it displaced. Nested calls on
+    /// the *same* thread (e.g. the closure itself calling `deploy_quiet`
+    /// again) are safe and don't deadlock: only the outermost call on a
+    /// thread installs the capture hook and takes the lock, and inner calls
+    /// transparently share it.
+    pub fn deploy_quiet<F>(func: F) -> (Outcome<T>, Option<String>)
+    where
+        F: FnOnce(&BreakTarget<T>) -> T,
+    {
+        let is_outermost = QUIET_DEPTH.with(|depth| {
+            let was = depth.get();
+            depth.set(was + 1);
+            was == 0
+        });
+        struct DepthGuard;
+        impl Drop for DepthGuard {
+            fn drop(&mut self) {
+                QUIET_DEPTH.with(|depth| depth.set(depth.get() - 1));
             }
         }
+        let _depth_guard = DepthGuard;
+
+        // Only the outermost call on this thread locks and swaps the hook;
+        // nested calls just run against whatever the outermost call already
+        // installed.
+        let _hook_guard = is_outermost.then(|| PANIC_HOOK_LOCK.lock().unwrap());
+        let prev_hook = is_outermost.then(|| {
+            CAPTURED_PANIC.with(|c| *c.borrow_mut() = None);
+            let prev_hook = panic::take_hook();
+            panic::set_hook(Box::new(|info| {
+                CAPTURED_PANIC.with(|c| *c.borrow_mut() = Some(info.to_string()));
+            }));
+            prev_hook
+        });
+
+        let resolution = Self::run(false, func).0;
+
+        if let Some(prev_hook) = prev_hook {
+            panic::set_hook(prev_hook);
+        }
+        let captured = CAPTURED_PANIC.with(|c| c.borrow_mut().take());
+
+        match resolution {
+            Resolution::Returned(v) => (Outcome::Returned(v), None),
+            Resolution::Broke(v) => (Outcome::Broke(v), None),
+            Resolution::Foreign(panic_val) => (Outcome::Panicked(panic_val), captured),
+        }
+    }
+
+    /// Like `deploy`, but also reports the source location of the
+    /// `break_with` call which produced the result, if any. Returns `None`
+    /// for the location when the closure completed normally rather than
+    /// breaking. This is useful when several code paths can break to the
+    /// same target and the caller needs to know which one fired.
+    pub fn deploy_with_location<F>(func: F) -> (T, Option<&'static Location<'static>>)
+    where
+        F: FnOnce(&BreakTarget<T>) -> T,
+    {
+        let (resolution, target) = Self::run(false, func);
+        match resolution {
+            Resolution::Returned(v) => (v, None),
+            Resolution::Broke(v) => (v, target.location.into_inner()),
+            Resolution::Foreign(panic_val) => panic::resume_unwind(panic_val),
+        }
+    }
+
+    /// Like `deploy`, but captures a `Backtrace` at the `break_with` call
+    /// site, returned alongside the broken value. As with `Backtrace::capture`,
+    /// frames are only actually collected when `RUST_BACKTRACE` or
+    /// `RUST_LIB_BACKTRACE` is set; otherwise a disabled `Backtrace` is
+    /// produced. The backtrace is `Backtrace::disabled()` when the closure
+    /// completes normally rather than breaking. Other `deploy` variants never
+    /// pay for backtrace capture, since tracing is only enabled here.
+    pub fn deploy_traced<F>(func: F) -> (T, Backtrace) where F: FnOnce(&BreakTarget<T>) -> T {
+        let (resolution, target) = Self::run(true, func);
+        match resolution {
+            Resolution::Returned(v) => (v, Backtrace::disabled()),
+            Resolution::Broke(v) => (
+                v,
+                target.backtrace.into_inner().unwrap_or_else(Backtrace::disabled),
+            ),
+            Resolution::Foreign(panic_val) => panic::resume_unwind(panic_val),
+        }
     }
 
     /// Aborts the current function, returning control to the BreakTarget's
     /// deploy point. The argument to this method will be the return value of
     /// the deploy method.
+    #[track_caller]
     pub fn break_with(&self, data: T) -> ! {
         // Record the information in the continuation object
-        *self.0.borrow_mut() = Some(data);
-
-        // Create an unwind sentinel object. Use our address as the address for
-        // the zero sized type BreakRequest such that we can communicate that
-        // we are the Continuation which is being triggered, while not breaking
-        // anything, as BreakRequest won't actually allocate any memory on the
-        // heap, and thus the box destructor will be a no-op.
-        let unwind_box: Box<BreakRequest> = unsafe {
-            Box::from_raw(self as *const Self as *mut Self as *mut BreakRequest)
+        *self.value.borrow_mut() = Some(data);
+        self.location.set(Some(Location::caller()));
+        if self.trace {
+            *self.backtrace.borrow_mut() = Some(Backtrace::capture());
+        }
+
+        // Unwind with a genuine boxed BreakRequest tagged with our id, so the
+        // matching deploy call can recognize it regardless of where on the
+        // stack this BreakTarget happens to live.
+        panic::resume_unwind(Box::new(BreakRequest { id: self.id }));
+    }
+}
+
+/// The result of a `BreakTarget::try_deploy` call, distinguishing a normal
+/// return from a break, and from an unrelated panic which occurred inside
+/// the deployed closure.
+#[derive(Debug)]
+pub enum Outcome<T> {
+    /// The closure returned normally, producing this value.
+    Returned(T),
+    /// The closure called `break_with`, producing this value.
+    Broke(T),
+    /// The closure panicked for a reason unrelated to this target; the
+    /// payload is handed back rather than being re-raised.
+    Panicked(Box<dyn Any + Send>),
+}
+
+/// A DynBreakRequest is the panic payload used to unwind toward a
+/// DynBreakTarget. Like BreakRequest it carries the id of the target it's
+/// routed to, alongside the boxed value being broken with.
+struct DynBreakRequest {
+    id: u64,
+    value: Box<dyn Any + Send>,
+}
+
+/// A break target which, unlike `BreakTarget<T>`, isn't tied to a single
+/// value type. Each inner scope can break with whatever `Any + Send` value
+/// makes sense for it, and the caller of `deploy` downcasts the result to
+/// whichever type it expects from that call site. This suits a single
+/// top-level escape hatch shared by heterogeneous nested computations, such
+/// as early-exit from a deeply nested parser where different layers produce
+/// different result types.
+#[derive(Debug)]
+pub struct DynBreakTarget {
+    id: u64,
+}
+
+impl DynBreakTarget {
+    /// Deploy a dynamically-typed break target. The target will be passed by
+    /// reference to the argument closure. If no inner scope breaks to it,
+    /// the closure's own return value (already boxed as `Box<dyn Any +
+    /// Send>`) is produced.
+    pub fn deploy<F>(func: F) -> Box<dyn Any + Send>
+    where
+        F: FnOnce(&DynBreakTarget) -> Box<dyn Any + Send>,
+    {
+        let target = DynBreakTarget {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
         };
 
-        // Use the resume_unwind function to unwind rather than panic! such that
-        // the object isn't double-boxed,
-        panic::resume_unwind(unwind_box);
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| func(&target))) {
+            Ok(v) => v,
+            Err(panic_val) => {
+                match panic_val.downcast::<DynBreakRequest>() {
+                    Ok(req) if req.id == target.id => req.value,
+                    Ok(req) => panic::resume_unwind(req),
+                    Err(panic_val) => panic::resume_unwind(panic_val),
+                }
+            }
+        }
+    }
+
+    /// Aborts the current function, returning control to the
+    /// DynBreakTarget's deploy point. The value is boxed as `Box<dyn Any +
+    /// Send>` and produced as the result of the deploy call, for the caller
+    /// to downcast.
+    pub fn break_with<V: Any + Send>(&self, data: V) -> ! {
+        panic::resume_unwind(Box::new(DynBreakRequest {
+            id: self.id,
+            value: Box::new(data),
+        }));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BreakTarget;
+    use super::{BreakTarget, DynBreakTarget, Outcome};
     use std::panic;
 
     #[test]
@@ -152,4 +417,173 @@ mod tests {
             assert!(false, "should panic");
         }
     }
+
+    #[test]
+    fn try_deploy_classifies_outcomes() {
+        match BreakTarget::try_deploy(|_| 20) {
+            Outcome::Returned(v) => assert_eq!(v, 20),
+            _ => assert!(false, "should have returned normally"),
+        }
+
+        match BreakTarget::try_deploy(|t| {
+            t.break_with(10);
+        }) {
+            Outcome::Broke(v) => assert_eq!(v, 10),
+            _ => assert!(false, "should have broken"),
+        }
+
+        match panic::catch_unwind(|| BreakTarget::try_deploy(|_| panic!(1u32))) {
+            Ok(Outcome::Panicked(e)) => assert_eq!(e.downcast_ref::<u32>(), Some(&1u32)),
+            _ => assert!(false, "should have captured the foreign panic"),
+        }
+    }
+
+    #[test]
+    fn deploy_with_location_reports_break_site() {
+        let (value, location) = BreakTarget::deploy_with_location(|t| {
+            t.break_with(1);
+        });
+        assert_eq!(value, 1);
+        let location = location.expect("break_with should have recorded a location");
+        assert_eq!(location.file(), file!());
+
+        let (value, location) = BreakTarget::deploy_with_location(|_| 20);
+        assert_eq!(value, 20);
+        assert!(location.is_none());
+    }
+
+    #[test]
+    fn deploy_traced_reports_a_backtrace() {
+        let (value, bt) = BreakTarget::deploy_traced(|t| {
+            t.break_with(1);
+        });
+        assert_eq!(value, 1);
+        // We don't assert on `bt`'s frames, since capture is gated on the
+        // RUST_BACKTRACE/RUST_LIB_BACKTRACE environment variables; just make
+        // sure asking for the backtrace of a break doesn't panic.
+        let _ = bt.status();
+
+        let (value, bt) = BreakTarget::deploy_traced(|_| 20);
+        assert_eq!(value, 20);
+        assert_eq!(bt.status(), std::backtrace::BacktraceStatus::Disabled);
+    }
+
+    #[test]
+    fn dyn_break_target_routes_heterogeneous_values() {
+        let res = DynBreakTarget::deploy(|t| {
+            t.break_with("broke out".to_string());
+        });
+        assert_eq!(res.downcast_ref::<String>(), Some(&"broke out".to_string()));
+
+        let res = DynBreakTarget::deploy(|_| Box::new(20i32));
+        assert_eq!(res.downcast_ref::<i32>(), Some(&20));
+    }
+
+    #[test]
+    fn deploy_quiet_captures_panic_message_and_stays_silent_on_break() {
+        match BreakTarget::deploy_quiet(|t| {
+            t.break_with(1);
+        }) {
+            (Outcome::Broke(v), captured) => {
+                assert_eq!(v, 1);
+                assert!(captured.is_none());
+            }
+            _ => assert!(false, "should have broken"),
+        }
+
+        match BreakTarget::deploy_quiet(|_| panic!("boom")) {
+            (Outcome::Panicked(e), captured) => {
+                assert_eq!(e.downcast_ref::<&str>(), Some(&"boom"));
+                assert!(captured.unwrap().contains("boom"));
+            }
+            _ => assert!(false, "should have captured the panic"),
+        }
+    }
+
+    #[test]
+    fn deploy_quiet_nests_on_the_same_thread_without_deadlocking() {
+        let (outer, outer_captured) = BreakTarget::deploy_quiet(|_| {
+            let (inner, inner_captured) = BreakTarget::deploy_quiet(|_| panic!("inner boom"));
+            match inner {
+                Outcome::Panicked(e) => {
+                    assert_eq!(e.downcast_ref::<&str>(), Some(&"inner boom"));
+                    assert!(inner_captured.unwrap().contains("inner boom"));
+                }
+                _ => assert!(false, "inner call should have captured its panic"),
+            }
+            20
+        });
+        match outer {
+            Outcome::Returned(v) => assert_eq!(v, 20),
+            _ => assert!(false, "outer call should have returned normally"),
+        }
+        assert!(outer_captured.is_none());
+    }
+
+    #[test]
+    fn deploy_quiet_survives_concurrent_callers() {
+        use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        // Wrap whatever hook is currently installed (libtest's own, under
+        // `cargo test`) with a sentinel so we can tell, after the race below,
+        // whether the *same* hook is still the one installed -- rather than
+        // one of the capture closures deploy_quiet installed internally and
+        // failed to fully unwind.
+        let sentinel_fired = Arc::new(AtomicBool::new(false));
+        let outer_hook = Arc::new(panic::take_hook());
+        let wrapped_hook = Arc::clone(&outer_hook);
+        let hook_sentinel = Arc::clone(&sentinel_fired);
+        panic::set_hook(Box::new(move |info| {
+            hook_sentinel.store(true, AtomicOrdering::SeqCst);
+            wrapped_hook(info);
+        }));
+
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    for j in 0..50 {
+                        let (outcome, captured) =
+                            BreakTarget::deploy_quiet(|_| panic!("thread {} iter {}", i, j));
+                        match outcome {
+                            Outcome::Panicked(_) => {
+                                let captured = captured.expect("panic message should be captured");
+                                assert!(captured.contains(&format!("thread {} iter {}", i, j)));
+                            }
+                            _ => assert!(false, "should have captured the panic"),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        sentinel_fired.store(false, AtomicOrdering::SeqCst);
+        let _ = panic::catch_unwind(|| panic!("after the race"));
+        assert!(
+            sentinel_fired.load(AtomicOrdering::SeqCst),
+            "the hook installed before the race should still be the one \
+             installed after it -- deploy_quiet must not leak a capture hook"
+        );
+
+        // Restore whatever was installed before this test, for other tests.
+        let _ = panic::take_hook();
+        panic::set_hook(Box::new(move |info| outer_hook(info)));
+    }
+
+    #[test]
+    fn dyn_break_target_unwinds_to_outer() {
+        let res = DynBreakTarget::deploy(|t| {
+            DynBreakTarget::deploy(|_| t.break_with(1i32));
+            unreachable!();
+        });
+        assert_eq!(res.downcast_ref::<i32>(), Some(&1));
+    }
 }